@@ -53,25 +53,184 @@
 //! A conversion from `&mut StatusPacket` to `&mut Packet` is not included,
 //! as altering the `Packet`-structure might violate invariants required
 //! by `StatusPacket`.
+//!
+//! Internally, every subtype is described by a single `Subtype` impl that
+//! carries its base and error as associated types rather than as separate
+//! generic parameters on a shared trait, so generic code over "any subtype
+//! of a given base" can name the base and error through `S::Base` and
+//! `S::Error` instead of spelling them out at every call site. The
+//! conversion itself is not a method of `Subtype` but the `TryFrom` impl
+//! generated for each subtype, so such code bounds on both, e.g.
+//! `fn parse<S>(p: Packet) -> Result<S, S::Error>
+//! where S: Subtype<Base = Packet> + TryFrom<Packet, Err = S::Error>`.
+//!
+//! For the common case of a single-field discriminant compared against a
+//! literal, the companion `pcast-derive` crate provides
+//! `#[derive(Subtype)]` so the `Subtype` impl above does not need to be
+//! written out by hand.
+//!
+//! `DerefMut` from a subtype to its base is deliberately not provided,
+//! because mutating base fields through it could invalidate the subtype's
+//! discriminant. `Subtype::modify_as_base` offers a safer middle ground: it
+//! hands out a [`BaseGuard`] that derefs mutably to the base, and
+//! re-validates the subtype on drop.
+
+/// Marks `Self` as a validated, same-layout subtype of `Self::Base`.
+///
+/// This describes the relationship (what the base/error types are, and how
+/// to validate one); the conversions from a `Base` to a `Self` are not part
+/// of this trait at all. Coherence rules forbid a single blanket `impl<S:
+/// Subtype> TryFrom<S::Base> for S` (the bare type parameter `S` is not a
+/// local type), so those are instead generated per subtype, as concrete
+/// `impl`s, by the `subtype_of!` macro (or `#[derive(Subtype)]`) alongside
+/// the `Subtype` impl.
+///
+/// `Subtype` is `unsafe` to implement, though: unlike `check`, which is
+/// just a validation predicate, [`modify_as_base`](Subtype::modify_as_base)
+/// is a *default* method on this trait, and it hands back a [`BaseGuard`]
+/// that `Deref`/`DerefMut`s to `Self::Base` through a raw pointer cast, for
+/// any `Self: Subtype` -- not just ones built by `subtype_of!`/derive. So
+/// the unsafe contract belongs to the trait itself: implementing `Subtype`
+/// for `Self` asserts that `Self` and `Self::Base` agree in size, and that
+/// `Self` is not more strictly aligned than `Self::Base`. `subtype_of!` and
+/// `#[derive(Subtype)]` uphold that contract and additionally check it at
+/// compile time (see the `size_of`/`align_of` asserts in `subtype_of!`'s
+/// expansion), so they remain the only things that should ever write the
+/// `unsafe impl`.
+pub unsafe trait Subtype: Sized {
+    /// The wider type this subtype is carved out of.
+    type Base;
+
+    /// The error returned when a `Base` does not match this subtype.
+    type Error;
+
+    /// Checks whether `base` is a valid instance of `Self`.
+    fn check(base: &Self::Base) -> Result<(), Self::Error>;
+
+    /// Grants temporary mutable access to `self` through its `Base` type.
+    ///
+    /// Unlike a `DerefMut` to `Base`, this is safe: the returned
+    /// [`BaseGuard`] re-runs `check` when it is dropped and panics if the
+    /// edit invalidated the subtype's discriminant. Call
+    /// [`BaseGuard::try_finish`] instead of letting the guard drop to get a
+    /// `Result` back rather than a panic.
+    fn modify_as_base<'a>(&'a mut self) -> BaseGuard<'a, Self> {
+        BaseGuard { sub: self }
+    }
+}
 
-pub trait SubtypeCheck<F, T, E> {
-    fn check_is_valid_subtype(&self) -> Result<(), E>;
+/// RAII guard returned by [`Subtype::modify_as_base`].
+///
+/// Derefs mutably to `S::Base` so callers can edit the underlying bytes;
+/// re-validates `S::check` on drop (panicking on failure) or via the
+/// non-panicking [`BaseGuard::try_finish`].
+pub struct BaseGuard<'a, S: Subtype + 'a> {
+    sub: &'a mut S,
 }
 
+impl<'a, S: Subtype> BaseGuard<'a, S> {
+    fn base_ref(&self) -> &S::Base {
+        unsafe { &*(self.sub as *const S as *const S::Base) }
+    }
+
+    /// Re-validates now instead of on drop, returning the mismatch error
+    /// (if any) rather than panicking.
+    pub fn try_finish(self) -> Result<(), S::Error> {
+        let result = S::check(self.base_ref());
+        ::std::mem::forget(self);
+        result
+    }
+}
+
+impl<'a, S: Subtype> ::std::ops::Deref for BaseGuard<'a, S> {
+    type Target = S::Base;
+
+    fn deref(&self) -> &S::Base {
+        self.base_ref()
+    }
+}
+
+impl<'a, S: Subtype> ::std::ops::DerefMut for BaseGuard<'a, S> {
+    fn deref_mut(&mut self) -> &mut S::Base {
+        unsafe { &mut *(self.sub as *mut S as *mut S::Base) }
+    }
+}
+
+impl<'a, S: Subtype> Drop for BaseGuard<'a, S> {
+    fn drop(&mut self) {
+        if S::check(self.base_ref()).is_err() {
+            panic!("BaseGuard: mutation through modify_as_base() invalidated the subtype invariant");
+        }
+    }
+}
+
+/// Declares `$sub` as a [`Subtype`] of `$base`, validated by `$check_fn`.
+///
+/// This fills in the `Subtype` impl (the `Base`/`Error` associated types
+/// and the `check` function body) and, since a blanket `TryFrom`/`Deref`
+/// over a bare `Subtype` type parameter would violate coherence (see the
+/// `Subtype` docs), also generates the concrete `TryFrom`/`Deref` impls for
+/// this particular `$base`/`$sub` pair. Those go through a pointer cast
+/// rather than a plain `transmute`, so the compiler does not check for us
+/// that `$sub` and `$base` agree in layout. This macro emits compile-time
+/// assertions instead: `$sub` must have the same size as `$base` and must
+/// not be more strictly aligned than it, so a pair that breaks either is a
+/// build error instead of an out-of-bounds read, or a misaligned `$sub`
+/// view of a `$base`, at run time. A less strictly aligned `$sub` of the
+/// same size is accepted. In particular, a `#[repr(C, packed)]` sub type
+/// over a non-packed base is rejected here if the packing changes its size,
+/// and a non-packed sub type over a packed base is rejected if it is more
+/// strictly aligned than the base, e.g.:
+///
+/// ```compile_fail
+/// #[macro_use]
+/// extern crate pcast;
+///
+/// #[repr(C)]
+/// pub struct Base {
+///     packet_type: u8,
+///     dummy: u32,
+/// }
+///
+/// #[repr(C, packed)]
+/// pub struct Sub {
+///     packet_type: u8,
+///     dummy: u32,
+/// }
+///
+/// subtype_of!(Base => Sub | () { Ok(()) });
+///
+/// fn main() {}
+/// ```
+///
+/// If `$check_fn` needs to look at `base`, name it explicitly with
+/// `|$param| { .. }` instead of writing `base` directly in the block:
+///
+/// ```ignore
+/// subtype_of!(Packet => StatusPacket | () |base| {
+///     if base.packet_type == 0x02 { Ok(()) } else { Err(()) }
+/// });
+/// ```
+///
+/// A plain `{ .. }` block can't refer to a `base` this macro binds for it --
+/// macro hygiene means an identifier this macro's own definition introduces
+/// (the `base` in `fn check(base: &$base)`) is not visible to tokens written
+/// at the call site, even when spelled the same way. Naming the parameter
+/// via `$param:ident`, captured from the call site itself, keeps it in the
+/// caller's hygiene context, so the block that follows can refer to it.
 #[macro_export]
 macro_rules! subtype_of {
     ($base:ty => $sub:ty | $cerr:ty $check_fn:block) => (
-        impl $crate::SubtypeCheck<$base, $sub, $cerr> for $base {
-            fn check_is_valid_subtype(&self) -> Result<(), $cerr> $check_fn
-        }
+        $crate::subtype_of!($base => $sub | $cerr |_base| $check_fn);
+    );
 
-        impl ::std::ops::Deref for $sub {
-            type Target = $base;
+    ($base:ty => $sub:ty | $cerr:ty |$param:ident| $check_fn:block) => (
+        unsafe impl $crate::Subtype for $sub {
+            type Base = $base;
+            type Error = $cerr;
 
             #[inline(always)]
-            fn deref(&self) -> &$base {
-                unsafe { ::std::mem::transmute::<&$sub, &$base>(self) }
-            }
+            fn check($param: &$base) -> Result<(), $cerr> $check_fn
         }
 
         impl ::std::convert::TryFrom<$base> for $sub {
@@ -79,8 +238,10 @@ macro_rules! subtype_of {
 
             #[inline(always)]
             fn try_from(base: $base) -> Result<Self, Self::Err> {
-                try!($crate::SubtypeCheck::<$base, $sub, $cerr>::check_is_valid_subtype(&base));
-                Ok(unsafe { ::std::mem::transmute::<$base, $sub>(base) })
+                try!(<$sub as $crate::Subtype>::check(&base));
+                let sub = unsafe { ::std::mem::transmute_copy(&base) };
+                ::std::mem::forget(base);
+                Ok(sub)
             }
         }
 
@@ -88,9 +249,9 @@ macro_rules! subtype_of {
             type Err = $cerr;
 
             #[inline(always)]
-            fn try_from(base_ref: &$base) -> Result<Self, Self::Err> {
-                try!($crate::SubtypeCheck::<$base, $sub, $cerr>::check_is_valid_subtype(base_ref));
-                Ok(unsafe { ::std::mem::transmute::<&$base, &$sub>(base_ref) })
+            fn try_from(base_ref: &'a $base) -> Result<Self, Self::Err> {
+                try!(<$sub as $crate::Subtype>::check(base_ref));
+                Ok(unsafe { &*(base_ref as *const $base as *const $sub) })
             }
         }
 
@@ -98,18 +259,199 @@ macro_rules! subtype_of {
             type Err = $cerr;
 
             #[inline(always)]
-            fn try_from(base_ref: &mut $base) -> Result<Self, Self::Err> {
-                try!($crate::SubtypeCheck::<$base, $sub, $cerr>::check_is_valid_subtype(base_ref));
-                Ok(unsafe { ::std::mem::transmute::<&mut $base, &mut $sub>(base_ref) })
+            fn try_from(base_ref: &'a mut $base) -> Result<Self, Self::Err> {
+                try!(<$sub as $crate::Subtype>::check(base_ref));
+                Ok(unsafe { &mut *(base_ref as *mut $base as *mut $sub) })
             }
         }
 
+        impl ::std::ops::Deref for $sub {
+            type Target = $base;
+
+            #[inline(always)]
+            fn deref(&self) -> &$base {
+                unsafe { &*(self as *const $sub as *const $base) }
+            }
+        }
+
+        const _: () = assert!(
+            ::core::mem::size_of::<$sub>() == ::core::mem::size_of::<$base>()
+        );
+        const _: () = assert!(
+            ::core::mem::align_of::<$sub>() <= ::core::mem::align_of::<$base>()
+        );
+    )
+}
+
+/// Classifies a base packet into a per-discriminant enum.
+///
+/// `$disc` names the field on `$base` to read; each `$lit => $variant($ty),`
+/// arm maps one discriminant value to a subtype, and the mandatory
+/// `else => $variant($base)` arm keeps the value as the base type for
+/// anything that does not match one of the literals. The generated `match`
+/// is always exhaustive, so there is no way to define a `tagged_union!`
+/// that silently misclassifies an unrecognized packet.
+///
+/// Each discriminant is matched as a `literal`, not a full `pat` -- `pat`
+/// was tried first and rejected, keyword catch-all and all: putting `$lit:pat`
+/// in a `$(..),+,` repetition and following it with anything else is
+/// ambiguous no matter what that "anything else" is (confirmed against
+/// rustc: swapping the `_` catch-all for the keyword `else`, or for a plain
+/// identifier, still hits the same `local ambiguity ... pat` error). The
+/// real cause isn't the catch-all's token -- it's that the comma which ends
+/// the repetition's last item and the comma meant to separate it from the
+/// catch-all are the same physical comma, so the parser can't tell, on
+/// reaching it, whether to stay in the repetition or leave it. Moving the
+/// comma inside each repeated item (`$($lit => $var($vty),)+`, terminator
+/// rather than separator) removes that overlap, and narrowing `$lit` from
+/// `pat` to `literal` removes the rest of the ambiguity, since `literal`
+/// only ever starts on an actual literal token and so can never be mistaken
+/// for the `else` that follows. The cost is that a discriminant here must
+/// be a single literal -- no ranges, no `|`-alternation.
+///
+/// Both the by-value enum (`$kind`) and the by-reference enum (`$kind_ref`,
+/// used by `classify_ref`) are spelled out explicitly, since `macro_rules!`
+/// has no way to synthesize a `$kind` + `Ref` identifier on its own.
+#[macro_export]
+macro_rules! tagged_union {
+    (
+        $base:ty, $disc:ident => enum $kind:ident / $kind_ref:ident {
+            $($lit:literal => $var:ident($vty:ty),)+
+            else => $catch_var:ident($caty:ty) $(,)?
+        }
+    ) => (
+        pub enum $kind {
+            $($var($vty),)+
+            $catch_var($caty),
+        }
+
+        pub enum $kind_ref<'a> {
+            $($var(&'a $vty),)+
+            $catch_var(&'a $caty),
+        }
+
+        $(
+            const _: () = assert!(
+                ::core::mem::size_of::<$vty>() == ::core::mem::size_of::<$base>()
+            );
+            const _: () = assert!(
+                ::core::mem::align_of::<$vty>() <= ::core::mem::align_of::<$base>()
+            );
+        )+
+
+        impl $base {
+            /// Reads the `$disc` field and transmutes into the matching
+            /// variant, consuming `self`.
+            pub fn classify(self) -> $kind {
+                match self.$disc {
+                    $($lit => $kind::$var(unsafe {
+                        let sub = ::core::mem::transmute_copy(&self);
+                        ::core::mem::forget(self);
+                        sub
+                    }),)+
+                    _ => $kind::$catch_var(self),
+                }
+            }
+
+            /// Reads the `$disc` field and returns a reference to the
+            /// matching variant, borrowing `self`.
+            pub fn classify_ref(&self) -> $kind_ref<'_> {
+                match self.$disc {
+                    $($lit => $kind_ref::$var(unsafe {
+                        &*(self as *const $base as *const $vty)
+                    }),)+
+                    _ => $kind_ref::$catch_var(self),
+                }
+            }
+        }
     )
 }
 
+/// Generates byte-order-aware accessors for a field embedded at a raw byte
+/// offset inside a `#[repr(C)]` struct.
+///
+/// Each `$getter / $setter: $int [$offset] = be|le;` line reads (and
+/// writes) `size_of::<$int>()` bytes starting at `$offset` as `$int`,
+/// going through `$int::from_be_bytes`/`from_le_bytes` (and the matching
+/// `to_*_bytes` for the setter) so a multi-byte wire field comes out
+/// correctly regardless of the host's endianness, while still only ever
+/// touching the struct's raw bytes -- no intermediate copy of the whole
+/// struct is made. As with `tagged_union!`, both accessor names are
+/// spelled out explicitly, since `macro_rules!` has no way to build a
+/// `set_$field` identifier out of `$field` on its own.
+///
+/// The offset is bracketed (`[$offset]`) rather than following `$int`
+/// with `@`, and is a `literal` rather than an `expr`, because
+/// `macro_rules!` restricts what can follow a `ty`/`expr` fragment in a
+/// matcher (its "follow set"): a `ty` cannot be followed by `@`, and an
+/// `expr` cannot be followed by `=`. Wrapping the offset in brackets (a
+/// `ty` *can* be followed by `[`) and using `literal`, which carries no
+/// such restriction, keeps the grammar valid.
+///
+/// Like `subtype_of!`'s size/align asserts, each field also gets a
+/// compile-time bounds check that `$offset + size_of::<$int>()` fits
+/// within `$ty`, so a field that would read or write past the end of the
+/// struct is a build error rather than an out-of-bounds access at run
+/// time.
+#[macro_export]
+macro_rules! fields {
+    (on $ty:ty; $($getter:ident / $setter:ident : $int:ty [ $offset:literal ] = $endian:ident;)+) => {
+        $(
+            const _: () = assert!(
+                $offset + ::core::mem::size_of::<$int>() <= ::core::mem::size_of::<$ty>()
+            );
+        )+
+
+        impl $ty {
+            $(
+                $crate::fields!(@one $ty, $getter, $setter, $int, $offset, $endian);
+            )+
+        }
+    };
+
+    (@one $ty:ty, $getter:ident, $setter:ident, $int:ty, $offset:literal, be) => {
+        pub fn $getter(&self) -> $int {
+            <$int>::from_be_bytes($crate::fields!(@read $ty, $int, self, $offset))
+        }
+
+        pub fn $setter(&mut self, value: $int) {
+            $crate::fields!(@write $ty, self, $offset, value.to_be_bytes());
+        }
+    };
+
+    (@one $ty:ty, $getter:ident, $setter:ident, $int:ty, $offset:literal, le) => {
+        pub fn $getter(&self) -> $int {
+            <$int>::from_le_bytes($crate::fields!(@read $ty, $int, self, $offset))
+        }
+
+        pub fn $setter(&mut self, value: $int) {
+            $crate::fields!(@write $ty, self, $offset, value.to_le_bytes());
+        }
+    };
+
+    (@read $ty:ty, $int:ty, $self:ident, $offset:literal) => {
+        unsafe {
+            *(($self as *const $ty as *const u8).add($offset)
+                as *const [u8; ::core::mem::size_of::<$int>()])
+        }
+    };
+
+    (@write $ty:ty, $self:ident, $offset:literal, $bytes:expr) => {
+        let bytes = $bytes;
+        unsafe {
+            ::core::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                ($self as *mut $ty as *mut u8).add($offset),
+                bytes.len(),
+            );
+        }
+    };
+}
+
 #[cfg(test)]
 mod test {
     use ::std::convert::TryInto;
+    use super::Subtype;
 
     #[repr(C)]
     pub struct Packet {
@@ -144,6 +486,30 @@ mod test {
         unused: [u8; 3],
     }
 
+    // Same layout as `Packet`, but its `check` actually looks at
+    // `packet_type` -- used to exercise `modify_as_base`'s re-validation.
+    #[repr(C)]
+    pub struct GuardedPacket {
+        packet_type: u8,
+        data: [u8; 7],
+    }
+
+    // Wire layout with the 4 byte big-endian node id described on `Packet`
+    // above (at offset 1) and an 8 byte big-endian data field at offset 6.
+    #[repr(C)]
+    pub struct WirePacket {
+        packet_type: u8,
+        node_id_be: [u8; 4],
+        flag: u8,
+        data_field_be: [u8; 8],
+    }
+
+    fields! {
+        on WirePacket;
+        node_id / set_node_id: u32 [1] = be;
+        data_field / set_data_field: u64 [6] = be;
+    }
+
     pub struct PongConvError {
 
     }
@@ -157,6 +523,15 @@ mod test {
     subtype_of!(Packet => StatusPacket | () {
         Ok(())
     });
+    subtype_of!(Packet => GuardedPacket | () |base| {
+        if base.packet_type == 0x02 { Ok(()) } else { Err(()) }
+    });
+
+    tagged_union!(Packet, packet_type => enum PacketKind / PacketKindRef {
+        0x02 => Status(StatusPacket),
+        0x05 => Ping(PingPacket),
+        else => Unknown(Packet),
+    });
 
     #[derive(Debug)]
     pub enum ConversionError {}
@@ -170,6 +545,10 @@ mod test {
             self.data = data
         }
 
+        pub fn set_packet_type(&mut self, packet_type: u8) {
+            self.packet_type = packet_type
+        }
+
         pub fn new(packet_type: u8, data: [u8; 7]) -> Packet {
             Packet {
                 packet_type: packet_type,
@@ -275,4 +654,93 @@ mod test {
 
         swallow_status_packet(s);
     }
+
+    #[test]
+    fn classify_dispatches_by_discriminant() {
+        let status = Packet::new(2, b"0123456".to_owned());
+        match status.classify() {
+            PacketKind::Status(s) => assert_eq!(s.get_status_2(), 0x36),
+            _ => panic!("expected Status"),
+        }
+
+        let ping = Packet::new(5, b"0123456".to_owned());
+        match ping.classify() {
+            PacketKind::Ping(_) => {}
+            _ => panic!("expected Ping"),
+        }
+
+        let unknown = Packet::new(0xff, b"0123456".to_owned());
+        match unknown.classify() {
+            PacketKind::Unknown(p) => assert_eq!(p.get_raw_payload(), b"0123456"),
+            _ => panic!("expected Unknown"),
+        }
+    }
+
+    #[test]
+    fn classify_ref_borrows_instead_of_consuming() {
+        let owned = Packet::new(2, b"0123456".to_owned());
+
+        match owned.classify_ref() {
+            PacketKindRef::Status(s) => assert_eq!(s.get_status_2(), 0x36),
+            _ => panic!("expected Status"),
+        }
+
+        // still usable: classify_ref only borrowed `owned`
+        send(&owned);
+    }
+
+    #[test]
+    fn modify_as_base_allows_editing_base_fields() {
+        let mut owned = Packet::new(2, b"0123456".to_owned());
+        let status_mut_ref: &mut StatusPacket = (&mut owned).try_into().unwrap();
+
+        {
+            let mut guard = status_mut_ref.modify_as_base();
+            guard.set_raw_payload(b"xxxxxxx".to_owned());
+        }
+
+        assert_eq!(status_mut_ref.get_raw_payload(), b"xxxxxxx");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalidated the subtype invariant")]
+    fn modify_as_base_panics_if_discriminant_no_longer_validates() {
+        let mut owned = Packet::new(2, b"0123456".to_owned());
+        let guarded: &mut GuardedPacket = (&mut owned).try_into().unwrap();
+
+        let mut guard = guarded.modify_as_base();
+        guard.set_packet_type(0x99);
+        // dropping the guard here re-validates and panics
+    }
+
+    #[test]
+    fn modify_as_base_try_finish_reports_errors_instead_of_panicking() {
+        let mut owned = Packet::new(2, b"0123456".to_owned());
+        let guarded: &mut GuardedPacket = (&mut owned).try_into().unwrap();
+
+        let mut guard = guarded.modify_as_base();
+        guard.set_packet_type(0x99);
+        assert!(guard.try_finish().is_err());
+    }
+
+    #[test]
+    fn fields_macro_is_byte_order_aware() {
+        let mut w = WirePacket {
+            packet_type: 0x12,
+            node_id_be: [0; 4],
+            flag: 0,
+            data_field_be: [0; 8],
+        };
+
+        w.set_node_id(1);
+        assert_eq!(w.node_id_be, [0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(w.node_id(), 1);
+
+        w.set_data_field(0x0123456789abcdef);
+        assert_eq!(
+            w.data_field_be,
+            [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]
+        );
+        assert_eq!(w.data_field(), 0x0123456789abcdef);
+    }
 }